@@ -1,91 +1,554 @@
 extern crate clap;
 extern crate hyper;
+extern crate serde;
+#[macro_use]
+extern crate serde_derive;
 extern crate serde_json;
 extern crate chrono;
 
 use std::fmt::{Display, Formatter};
+use std::sync::mpsc;
 use chrono::{DateTime, Local};
+use serde::{Serialize, Serializer};
 
-struct Request {
-    stop_point_name: Option<String>
+/// Describes a concrete URA deployment: its base URL and any quirks in the
+/// wire format. `Request::send` is written against this trait rather than a
+/// raw URL so the same query logic works across providers.
+trait UraProfile {
+    /// Base URL of the `instant_V1` endpoint, including trailing `?`.
+    fn base_url(&self) -> &str;
+
+    /// `ReturnList` field set to request. Providers agree closely enough
+    /// that the default covers ASEAG, TfL and most others.
+    fn return_list(&self) -> &str {
+        "StopPointName,LineName,DestinationText,EstimatedTime,TripID"
+    }
+
+    /// Extracts the response timestamp from the leading `[0, ...]` version
+    /// line. Exposed as a trait method, rather than a free function, so a
+    /// profile can override the decoding if its deployment encodes the
+    /// millisecond timestamp in some non-standard way (e.g. quoted as a
+    /// string); none of the profiles shipped here need to. Returns
+    /// `Error::MalformedResponse` rather than panicking, so a backend that
+    /// sends an unexpected version line fails this one query gracefully.
+    fn parse_version_timestamp(&self, version_line: &serde_json::Value) -> Result<DateTime<Local>, Error> {
+        let malformed = || Error::MalformedResponse(format!("{:?}", version_line));
+        let ura_version = try!(version_line.as_array().ok_or_else(malformed));
+        let timestamp = try!(ura_version.get(2).and_then(|v| v.as_i64()).ok_or_else(malformed));
+        Ok(datetime_from_millis(timestamp))
+    }
+
+    /// URL of the live `stream_V1` endpoint, including trailing `?`.
+    /// Defaults to swapping `instant_V1` for `stream_V1` in `base_url`,
+    /// which holds for every deployment this crate ships a profile for.
+    fn stream_url(&self) -> String {
+        self.base_url().replace("instant_V1", "stream_V1")
+    }
+}
+
+struct AseagProfile;
+
+impl UraProfile for AseagProfile {
+    fn base_url(&self) -> &str {
+        "http://ivu.aseag.de/interfaces/ura/instant_V1?"
+    }
+}
+
+struct TflProfile;
+
+impl UraProfile for TflProfile {
+    fn base_url(&self) -> &str {
+        "https://countdown.api.tfl.gov.uk/interfaces/ura/instant_V1?"
+    }
+}
+
+/// Fallback profile for any URA deployment that isn't shipped as a named
+/// profile, e.g. Reading Buses. Takes the base URL (including trailing `?`)
+/// verbatim and assumes the common field set.
+struct CustomUrlProfile {
+    url: String
+}
+
+impl UraProfile for CustomUrlProfile {
+    fn base_url(&self) -> &str {
+        self.url.as_str()
+    }
+}
+
+/// Resolves a `--profile` argument to a concrete profile: the names `tfl`
+/// and `aseag`, or else any other string is treated as a custom base URL.
+fn choose_profile(spec: &str) -> Box<dyn UraProfile + Send + Sync> {
+    match spec {
+        "tfl" => Box::new(TflProfile),
+        "aseag" => Box::new(AseagProfile),
+        url => Box::new(CustomUrlProfile { url: url.to_string() })
+    }
+}
+
+enum Request {
+    Predictions { stop_point_name: Option<String> },
+    Near { lat: f64, lon: f64, radius_m: f64 },
+    Search { query: String }
 }
 
 impl Request {
     fn with_stop_point_name(stop_point_name: String) -> Self {
-        Request {
+        Request::Predictions {
             stop_point_name: Some(stop_point_name)
         }
     }
 
-    fn send(self, base_url: String) -> Result<Predictions, Error> {
-        use std::io::{BufReader, BufRead};
-        use hyper::client::Client;
-        use hyper::status::StatusCode;
-        use serde_json::Value;
-
-        let mut args = vec!("ReturnList=StopPointName,LineName,DestinationText,EstimatedTime,TripID"
-                            .to_string());
-        if let Some(ref stop_point_name) = self.stop_point_name {
-            args.push("StopPointName=".to_string() + stop_point_name.as_str());
-        }
-        let url = base_url + args.join("&").as_str();
-        //println!("request url: {}", url);
-
-        let client = Client::new();
-        match client.get(&url).send() {
-            Ok(response) => {
-                match response.status {
-                    StatusCode::Ok => {
-                        let mut predictions: Vec<Prediction> = Vec::new();
-                        let mut lines = BufReader::new(response).lines();
-                        let ura_version_json =
-                            serde_json::from_str::<Value>(lines.next().unwrap().unwrap().as_str()).unwrap();
-                        let ura_version = ura_version_json.as_array().unwrap();
-                        let timestamp = ura_version[2].as_i64().unwrap();
-                        let time = datetime_from_millis(timestamp);
-                        for line in lines {
-                            let prediction_json: Value =
-                                serde_json::from_str::<Value>(line.unwrap().as_str()).unwrap();
-                            let prediction_array_json = prediction_json.as_array().unwrap();
-                            let stop_point_name = prediction_array_json[1].as_string().unwrap().to_string();
-                            let line_name = prediction_array_json[2].as_string().unwrap().to_string();
-                            let destination_text = prediction_array_json[3].as_string().unwrap().to_string();
-                            let trip_id = prediction_array_json[4].as_u64().unwrap();
-                            let estimated_time =
-                                datetime_from_millis(prediction_array_json[5].as_i64().unwrap());
-                            predictions.push(Prediction {
-                                stop_point_name: stop_point_name,
-                                line_name: line_name,
-                                destination_text: destination_text,
-                                trip_id: trip_id,
-                                estimated_time: estimated_time
-                            });
+    fn near(lat: f64, lon: f64, radius_m: f64) -> Self {
+        Request::Near { lat: lat, lon: lon, radius_m: radius_m }
+    }
+
+    fn search(query: String) -> Self {
+        Request::Search { query: query }
+    }
+
+    fn send(self, profile: &dyn UraProfile) -> Result<QueryResult, Error> {
+        match self {
+            Request::Predictions { stop_point_name } =>
+                send_predictions(stop_point_name, profile).map(QueryResult::Predictions),
+            Request::Near { lat, lon, radius_m } =>
+                send_near(lat, lon, radius_m, profile).map(QueryResult::Predictions),
+            Request::Search { query } =>
+                send_search(query, profile).map(QueryResult::StopCandidates)
+        }
+    }
+
+    /// Like `send`, but against the live `stream_V1` endpoint: the HTTP
+    /// response is kept open on a background thread, which forwards each
+    /// incremental `StreamEvent` as it arrives. Only supported for
+    /// `Request::Predictions`.
+    fn send_stream(self, profile: &dyn UraProfile) -> Result<mpsc::Receiver<StreamEvent>, Error> {
+        match self {
+            Request::Predictions { stop_point_name } => stream_predictions(stop_point_name, profile),
+            Request::Near { .. } | Request::Search { .. } =>
+                panic!("send_stream only supports Request::Predictions")
+        }
+    }
+}
+
+enum QueryResult {
+    Predictions(Predictions),
+    StopCandidates(Vec<StopCandidate>)
+}
+
+fn send_predictions(stop_point_name: Option<String>, profile: &dyn UraProfile)
+                     -> Result<Predictions, Error> {
+    use std::io::{BufReader, BufRead};
+    use hyper::client::Client;
+    use hyper::status::StatusCode;
+
+    let mut args = vec!("ReturnList=".to_string() + profile.return_list());
+    if let Some(ref stop_point_name) = stop_point_name {
+        args.push("StopPointName=".to_string() + stop_point_name.as_str());
+    }
+    let url = profile.base_url().to_string() + args.join("&").as_str();
+    //println!("request url: {}", url);
+
+    let client = Client::new();
+    match client.get(&url).send() {
+        Ok(response) => {
+            match response.status {
+                StatusCode::Ok => {
+                    let mut predictions: Vec<Prediction> = Vec::new();
+                    let mut lines = BufReader::new(response).lines();
+                    let time = try!(parse_version_line(&mut lines, profile));
+                    for line in lines {
+                        let line = try!(line.map_err(|_|
+                            Error::MalformedResponse("<io error reading line>".to_string())));
+                        predictions.push(try!(parse_prediction_line(line.as_str())));
+                    }
+                    Ok(Predictions{
+                        time: time,
+                        predictions: predictions
+                    })
+                },
+                StatusCode::RangeNotSatisfiable => {
+                    match stop_point_name {
+                        Some(name) => {
+                            Err(Error::BadStopPointName(name))
+                        },
+                        None => {
+                            Err(Error::UnknownStatus(StatusCode::RangeNotSatisfiable))
                         }
-                        Ok(Predictions{
-                            time: time,
-                            predictions: predictions
-                        })
-                    },
-                    StatusCode::RangeNotSatisfiable => {
-                        match self.stop_point_name {
-                            Some(name) => {
-                                Err(Error::BadStopPointName(name))
-                            },
-                            None => {
-                                Err(Error::UnknownStatus(StatusCode::RangeNotSatisfiable))
+                    }
+                }
+                unknown => {
+                    Err(Error::UnknownStatus(unknown))
+                }
+            }
+        },
+        Err(error) => {
+            Err(Error::HyperError(error))
+        }
+    }
+}
+
+/// Reads and parses the leading `[0, ...]` version line that every
+/// `instant_V1`/`stream_V1` response starts with, returning its timestamp.
+/// An empty body, an IO error, or unparsable JSON all yield
+/// `Error::MalformedResponse` instead of panicking.
+fn parse_version_line<I: Iterator<Item = std::io::Result<String>>>(lines: &mut I, profile: &dyn UraProfile)
+                                                                    -> Result<DateTime<Local>, Error> {
+    use serde_json::Value;
+
+    let version_line = match lines.next() {
+        Some(Ok(line)) => line,
+        Some(Err(_)) => return Err(Error::MalformedResponse("<io error reading version line>".to_string())),
+        None => return Err(Error::MalformedResponse("<empty response>".to_string()))
+    };
+    let version_json: Value = try!(serde_json::from_str(version_line.as_str())
+        .map_err(|_| Error::MalformedResponse(version_line.clone())));
+    profile.parse_version_timestamp(&version_json)
+}
+
+/// Parses one `[1, StopPointName, LineName, DestinationText, TripID,
+/// EstimatedTime]` line. Any schema mismatch (wrong arity, wrong type)
+/// yields `Error::MalformedResponse` carrying the offending line instead of
+/// panicking, so a backend returning unexpected JSON fails this one stop's
+/// query gracefully rather than crashing the whole process.
+fn parse_prediction_line(line: &str) -> Result<Prediction, Error> {
+    use serde_json::Value;
+
+    let malformed = || Error::MalformedResponse(line.to_string());
+
+    let prediction_json: Value = try!(serde_json::from_str(line).map_err(|_| malformed()));
+    let array = try!(prediction_json.as_array().ok_or_else(malformed));
+    if array.len() < 6 {
+        return Err(malformed());
+    }
+    let stop_point_name = try!(array[1].as_string().ok_or_else(malformed)).to_string();
+    let line_name = try!(array[2].as_string().ok_or_else(malformed)).to_string();
+    let destination_text = try!(array[3].as_string().ok_or_else(malformed)).to_string();
+    let trip_id = try!(array[4].as_u64().ok_or_else(malformed));
+    let estimated_time_millis = try!(array[5].as_i64().ok_or_else(malformed));
+
+    Ok(Prediction {
+        stop_point_name: stop_point_name,
+        line_name: line_name,
+        destination_text: destination_text,
+        trip_id: trip_id,
+        estimated_time: datetime_from_millis(estimated_time_millis),
+        coords: None
+    })
+}
+
+/// Parses one `[1, StopPointName, Latitude, Longitude, LineName,
+/// DestinationText, EstimatedTime, TripID]` line from a `--near` Circle
+/// query. Any schema mismatch yields `Error::MalformedResponse` carrying the
+/// offending line instead of panicking.
+fn parse_near_prediction_line(line: &str) -> Result<Prediction, Error> {
+    use serde_json::Value;
+
+    let malformed = || Error::MalformedResponse(line.to_string());
+
+    let prediction_json: Value = try!(serde_json::from_str(line).map_err(|_| malformed()));
+    let array = try!(prediction_json.as_array().ok_or_else(malformed));
+    if array.len() < 7 {
+        return Err(malformed());
+    }
+    let stop_point_name = try!(array[1].as_string().ok_or_else(malformed)).to_string();
+    let latitude = try!(array[2].as_f64().ok_or_else(malformed));
+    let longitude = try!(array[3].as_f64().ok_or_else(malformed));
+    let line_name = try!(array[4].as_string().ok_or_else(malformed)).to_string();
+    let destination_text = try!(array[5].as_string().ok_or_else(malformed)).to_string();
+    let estimated_time_millis = try!(array[6].as_i64().ok_or_else(malformed));
+    let trip_id = try!(array.get(7).and_then(|v| v.as_u64()).ok_or_else(malformed));
+
+    Ok(Prediction {
+        stop_point_name: stop_point_name,
+        line_name: line_name,
+        destination_text: destination_text,
+        trip_id: trip_id,
+        estimated_time: datetime_from_millis(estimated_time_millis),
+        coords: Some((latitude, longitude))
+    })
+}
+
+/// A single incremental update from a `stream_V1` connection: an `[1,...]`
+/// line (full prediction, insert or replace), a `[2,...]` line (the trip
+/// left the board) or a `[3,...]` line (time update for an existing trip).
+enum StreamEvent {
+    Insert(Prediction),
+    Delete(TripId),
+    UpdateTime(TripId, DateTime<Local>)
+}
+
+/// Opens a `stream_V1` connection for `stop_point_name` and keeps reading it
+/// on a background thread, forwarding each parsed `StreamEvent` over the
+/// returned channel until the connection ends.
+fn stream_predictions(stop_point_name: Option<String>, profile: &dyn UraProfile)
+                       -> Result<mpsc::Receiver<StreamEvent>, Error> {
+    use std::io::{BufReader, BufRead};
+    use std::thread;
+    use hyper::client::Client;
+    use hyper::status::StatusCode;
+
+    let mut args = vec!("ReturnList=".to_string() + profile.return_list());
+    if let Some(ref stop_point_name) = stop_point_name {
+        args.push("StopPointName=".to_string() + stop_point_name.as_str());
+    }
+    let url = profile.stream_url() + args.join("&").as_str();
+
+    let client = Client::new();
+    match client.get(&url).send() {
+        Ok(response) => {
+            match response.status {
+                StatusCode::Ok => {
+                    let (tx, rx) = mpsc::channel();
+                    thread::spawn(move || {
+                        let lines = BufReader::new(response).lines();
+                        for line in lines {
+                            let line = match line {
+                                Ok(line) => line,
+                                Err(_) => break
+                            };
+                            if let Some(event) = parse_stream_line(line.as_str()) {
+                                if tx.send(event).is_err() {
+                                    break;
+                                }
                             }
                         }
+                    });
+                    Ok(rx)
+                },
+                unknown => {
+                    Err(Error::UnknownStatus(unknown))
+                }
+            }
+        },
+        Err(error) => {
+            Err(Error::HyperError(error))
+        }
+    }
+}
+
+/// Parses one line of a `stream_V1` response. Returns `None` for the
+/// leading `[0,...]` version line.
+fn parse_stream_line(line: &str) -> Option<StreamEvent> {
+    use serde_json::Value;
+
+    let json = match serde_json::from_str::<Value>(line) {
+        Ok(json) => json,
+        Err(_) => return None
+    };
+    let array = match json.as_array() {
+        Some(array) => array,
+        None => return None
+    };
+    match array.get(0).and_then(|v| v.as_i64()) {
+        Some(1) => {
+            if array.len() < 6 {
+                return None;
+            }
+            let stop_point_name = match array[1].as_string() {
+                Some(s) => s.to_string(),
+                None => return None
+            };
+            let line_name = match array[2].as_string() {
+                Some(s) => s.to_string(),
+                None => return None
+            };
+            let destination_text = match array[3].as_string() {
+                Some(s) => s.to_string(),
+                None => return None
+            };
+            let trip_id = match array[4].as_u64() {
+                Some(trip_id) => trip_id,
+                None => return None
+            };
+            let estimated_time = match array[5].as_i64() {
+                Some(millis) => datetime_from_millis(millis),
+                None => return None
+            };
+            Some(StreamEvent::Insert(Prediction {
+                stop_point_name: stop_point_name,
+                line_name: line_name,
+                destination_text: destination_text,
+                trip_id: trip_id,
+                estimated_time: estimated_time,
+                coords: None
+            }))
+        },
+        Some(2) => {
+            if array.len() < 2 {
+                return None;
+            }
+            array[1].as_u64().map(StreamEvent::Delete)
+        },
+        Some(3) => {
+            if array.len() < 3 {
+                return None;
+            }
+            match (array[1].as_u64(), array[2].as_i64()) {
+                (Some(trip_id), Some(millis)) =>
+                    Some(StreamEvent::UpdateTime(trip_id, datetime_from_millis(millis))),
+                _ => None
+            }
+        },
+        _ => None
+    }
+}
+
+/// Queries all departures within `radius_m` metres of `(lat, lon)` using the
+/// URA `Circle` filter, instead of naming a stop.
+fn send_near(lat: f64, lon: f64, radius_m: f64, profile: &dyn UraProfile)
+             -> Result<Predictions, Error> {
+    use std::io::{BufReader, BufRead};
+    use hyper::client::Client;
+    use hyper::status::StatusCode;
+
+    let url = profile.base_url().to_string()
+        + "ReturnList=StopPointName,Latitude,Longitude,LineName,DestinationText,EstimatedTime,TripID"
+        + format!("&Circle={},{},{}", lat, lon, radius_m).as_str();
+
+    let client = Client::new();
+    match client.get(&url).send() {
+        Ok(response) => {
+            match response.status {
+                StatusCode::Ok => {
+                    let mut predictions: Vec<Prediction> = Vec::new();
+                    let mut lines = BufReader::new(response).lines();
+                    let time = try!(parse_version_line(&mut lines, profile));
+                    for line in lines {
+                        let line = try!(line.map_err(|_|
+                            Error::MalformedResponse("<io error reading line>".to_string())));
+                        match parse_near_prediction_line(line.as_str()) {
+                            Ok(prediction) => predictions.push(prediction),
+                            Err(error) => eprintln!("error: skipping malformed line: {:?}", error)
+                        }
                     }
-                    unknown => {
-                        Err(Error::UnknownStatus(unknown))
+                    Ok(Predictions{
+                        time: time,
+                        predictions: predictions
+                    })
+                },
+                unknown => {
+                    Err(Error::UnknownStatus(unknown))
+                }
+            }
+        },
+        Err(error) => {
+            Err(Error::HyperError(error))
+        }
+    }
+}
+
+/// Queries the full stop list (no exact filter) and ranks it against
+/// `query` client-side: case-insensitive substring matches first, then the
+/// remainder ordered by Levenshtein distance.
+fn send_search(query: String, profile: &dyn UraProfile) -> Result<Vec<StopCandidate>, Error> {
+    use std::io::{BufReader, BufRead};
+    use hyper::client::Client;
+    use hyper::status::StatusCode;
+
+    let url = profile.base_url().to_string()
+        + "ReturnList=StopPointName,StopID,Latitude,Longitude";
+
+    let client = Client::new();
+    match client.get(&url).send() {
+        Ok(response) => {
+            match response.status {
+                StatusCode::Ok => {
+                    let mut candidates: Vec<StopCandidate> = Vec::new();
+                    let mut lines = BufReader::new(response).lines();
+                    lines.next(); // discard the leading version line
+                    for line in lines {
+                        let line = try!(line.map_err(|_|
+                            Error::MalformedResponse("<io error reading line>".to_string())));
+                        match parse_stop_candidate_line(line.as_str()) {
+                            Ok(candidate) => candidates.push(candidate),
+                            Err(error) => eprintln!("error: skipping malformed line: {:?}", error)
+                        }
                     }
+                    let query_lower = query.to_lowercase();
+                    candidates.sort_by_key(|c| {
+                        let name_lower = c.stop_point_name.to_lowercase();
+                        if name_lower.contains(query_lower.as_str()) {
+                            0
+                        } else {
+                            1 + levenshtein(name_lower.as_str(), query_lower.as_str())
+                        }
+                    });
+                    Ok(candidates)
+                },
+                unknown => {
+                    Err(Error::UnknownStatus(unknown))
                 }
-            },
-            Err(error) => {
-                Err(Error::HyperError(error))
             }
+        },
+        Err(error) => {
+            Err(Error::HyperError(error))
+        }
+    }
+}
+
+/// Parses one `[_, StopPointName, StopID, Latitude, Longitude]` line from a
+/// stop-search response; the leading record-type element is not checked.
+/// Any schema mismatch yields
+/// `Error::MalformedResponse` carrying the offending line instead of
+/// panicking.
+fn parse_stop_candidate_line(line: &str) -> Result<StopCandidate, Error> {
+    use serde_json::Value;
+
+    let malformed = || Error::MalformedResponse(line.to_string());
+
+    let stop_json: Value = try!(serde_json::from_str(line).map_err(|_| malformed()));
+    let array = try!(stop_json.as_array().ok_or_else(malformed));
+    if array.len() < 5 {
+        return Err(malformed());
+    }
+    let stop_point_name = try!(array[1].as_string().ok_or_else(malformed)).to_string();
+    let stop_id = try!(array[2].as_string().ok_or_else(malformed)).to_string();
+    let latitude = try!(array[3].as_f64().ok_or_else(malformed));
+    let longitude = try!(array[4].as_f64().ok_or_else(malformed));
+
+    Ok(StopCandidate {
+        stop_point_name: stop_point_name,
+        stop_id: stop_id,
+        coords: (latitude, longitude)
+    })
+}
+
+/// Classic Wagner-Fischer edit distance, used to rank stop names that don't
+/// contain the search query as a substring.
+fn levenshtein(a: &str, b: &str) -> usize {
+    let a: Vec<char> = a.chars().collect();
+    let b: Vec<char> = b.chars().collect();
+    let mut row: Vec<usize> = (0..=b.len()).collect();
+
+    for i in 1..=a.len() {
+        let mut prev = row[0];
+        row[0] = i;
+        for j in 1..=b.len() {
+            let cur = row[j];
+            row[j] = if a[i - 1] == b[j - 1] {
+                prev
+            } else {
+                1 + prev.min(row[j]).min(row[j - 1])
+            };
+            prev = cur;
         }
     }
+    row[b.len()]
+}
+
+#[derive(Clone, Debug)]
+struct StopCandidate {
+    stop_point_name: String,
+    stop_id: String,
+    coords: (f64, f64)
+}
+
+impl Display for StopCandidate {
+    fn fmt(&self, f: &mut Formatter) -> Result<(), std::fmt::Error> {
+        try!(write!(f, "{} (id {}) at {:.5},{:.5}",
+                    self.stop_point_name, self.stop_id, self.coords.0, self.coords.1));
+        Ok(())
+    }
 }
 
 fn datetime_from_millis(timestamp: i64) -> DateTime<Local> {
@@ -100,7 +563,12 @@ fn datetime_from_millis(timestamp: i64) -> DateTime<Local> {
 enum Error {
     HyperError(hyper::error::Error),
     BadStopPointName(String),
-    UnknownStatus(hyper::status::StatusCode)
+    UnknownStatus(hyper::status::StatusCode),
+    /// The backend's response couldn't be parsed as the URA schema we expect
+    /// (invalid JSON, wrong arity, wrong types, an empty body or a read
+    /// error). Carries the offending line, or a `<...>` placeholder where
+    /// there is no line to show.
+    MalformedResponse(String)
 }
 
 #[derive(Debug)]
@@ -109,21 +577,71 @@ struct Predictions {
     predictions: Vec<Prediction>
 }
 
+/// Hand-rolled rather than derived: each serialized prediction needs
+/// `wait_minutes`, which only makes sense relative to `Predictions::time`
+/// and so isn't a field `Prediction` itself carries.
+impl Serialize for Predictions {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        use serde::ser::SerializeStruct;
+
+        let predictions: Vec<SerializablePrediction> = self.predictions.iter()
+            .map(|p| SerializablePrediction::from_prediction(p, self.time))
+            .collect();
+
+        let mut state = try!(serializer.serialize_struct("Predictions", 2));
+        try!(state.serialize_field("time", &self.time.to_rfc3339()));
+        try!(state.serialize_field("predictions", &predictions));
+        state.end()
+    }
+}
+
+#[derive(Serialize)]
+struct SerializablePrediction<'a> {
+    stop_point_name: &'a str,
+    line_name: &'a str,
+    destination_text: &'a str,
+    trip_id: TripId,
+    estimated_time: String,
+    coords: Option<(f64, f64)>,
+    wait_minutes: i64
+}
+
+impl <'a> SerializablePrediction<'a> {
+    fn from_prediction(p: &'a Prediction, now: DateTime<Local>) -> Self {
+        SerializablePrediction {
+            stop_point_name: p.stop_point_name.as_str(),
+            line_name: p.line_name.as_str(),
+            destination_text: p.destination_text.as_str(),
+            trip_id: p.trip_id,
+            estimated_time: p.estimated_time.to_rfc3339(),
+            coords: p.coords,
+            wait_minutes: p.estimated_time.signed_duration_since(now).num_minutes()
+        }
+    }
+}
+
 impl Predictions {
-    fn format(&self, compact: bool) -> String {
+    /// Renders the predictions as a fixed-width table. When `origin` is
+    /// given, each prediction with known `coords` also shows its distance
+    /// from that point.
+    fn format(&self, compact: bool, origin: Option<(f64, f64)>) -> String {
         let now = self.time;
         let mut out = String::new();
         for p in self.predictions.iter() {
+            let distance = match (origin, p.coords) {
+                (Some(origin), Some(coords)) => format!(" {:.0}m", haversine_distance_m(origin, coords)),
+                _ => String::new()
+            };
             let line = match compact {
                 false => {
-                    format!("{:>3}min {:>4} {}\n",
+                    format!("{:>3}min {:>4} {}{}\n",
                             (p.estimated_time.signed_duration_since(now)).num_minutes(),
-                             p.line_name, p.destination_text)
+                             p.line_name, p.destination_text, distance)
                 },
                 true => {
-                    format!("{}min {} {}\n",
+                    format!("{}min {} {}{}\n",
                             (p.estimated_time.signed_duration_since(now)).num_minutes(),
-                             p.line_name, p.destination_text)
+                             p.line_name, p.destination_text, distance)
                 }
             };
             out.push_str(line.as_str());
@@ -134,11 +652,23 @@ impl Predictions {
 
 impl Display for Predictions {
     fn fmt(&self, f: &mut Formatter) -> Result<(), std::fmt::Error> {
-        try!(write!(f, "{}", self.format(false)));
+        try!(write!(f, "{}", self.format(false, None)));
         Ok(())
     }
 }
 
+/// Great-circle distance between two `(lat, lon)` points in metres, via the
+/// haversine formula.
+fn haversine_distance_m(a: (f64, f64), b: (f64, f64)) -> f64 {
+    const EARTH_RADIUS_M: f64 = 6_371_000.0;
+    let (lat1, lon1) = (a.0.to_radians(), a.1.to_radians());
+    let (lat2, lon2) = (b.0.to_radians(), b.1.to_radians());
+    let dlat = lat2 - lat1;
+    let dlon = lon2 - lon1;
+    let h = (dlat / 2.0).sin().powi(2) + lat1.cos() * lat2.cos() * (dlon / 2.0).sin().powi(2);
+    2.0 * EARTH_RADIUS_M * h.sqrt().asin()
+}
+
 trait PredictionsCombinator {
     fn intersect(self, ordered: bool) -> Option<Predictions>;
 }
@@ -194,20 +724,33 @@ struct Prediction {
     line_name: String,
     destination_text: String,
     trip_id: TripId,
-    estimated_time: DateTime<Local>
+    estimated_time: DateTime<Local>,
+    coords: Option<(f64, f64)>
 }
 
 fn main() {
-    use std::sync::mpsc::channel;
+    use std::sync::{mpsc::channel, Arc};
     use std::thread;
     use clap::{App, Arg};
 
     let arg_matches = App::new("travel_ura")
-        .about("Queries URA live bus APIs, like the one of Transport for London (TfL)")
+        .about("Queries URA live bus APIs, e.g. Transport for London (TfL) or ASEAG")
         .arg(Arg::with_name("STOP")
              .takes_value(true)
-             .multiple(true)
-             .required(true))
+             .multiple(true))
+        .arg(Arg::with_name("search")
+             .long("search")
+             .takes_value(true)
+             .value_name("QUERY")
+             .help("look up stop names matching QUERY instead of querying departures"))
+        .arg(Arg::with_name("near")
+             .long("near")
+             .takes_value(true)
+             .value_name("LAT,LON,RADIUS_M")
+             .help("show departures within RADIUS_M metres of LAT,LON instead of naming a stop"))
+        .arg(Arg::with_name("watch")
+             .long("watch")
+             .help("continuously update the departure board for a single STOP instead of querying once"))
         .arg(Arg::with_name("compact")
              .short("c")
              .long("compact")
@@ -216,37 +759,198 @@ fn main() {
              .short("O")
              .long("unordered")
              .help("do not filter out busses which do not visit the stops in the given order"))
+        .arg(Arg::with_name("profile")
+             .long("profile")
+             .takes_value(true)
+             .default_value("aseag")
+             .help("URA deployment to query: tfl, aseag, or a custom base URL"))
+        .arg(Arg::with_name("format")
+             .long("format")
+             .takes_value(true)
+             .default_value("text")
+             .possible_values(&["text", "json"])
+             .help("output format"))
         .get_matches();
 
-    let base_url = "http://ivu.aseag.de/interfaces/ura/instant_V1?";
-
     // parse arguments
-    let stops: Vec<String> = arg_matches.values_of("STOP").unwrap().map(|s| s.to_string()).collect();
     let compact_output = arg_matches.is_present("compact");
     let ordered = !arg_matches.is_present("unordered");
+    let format_output = arg_matches.value_of("format").unwrap();
+    let profile: Arc<dyn UraProfile + Send + Sync> =
+        Arc::from(choose_profile(arg_matches.value_of("profile").unwrap()));
+
+    if let Some(query) = arg_matches.value_of("search") {
+        match Request::search(query.to_string()).send(profile.as_ref()) {
+            Ok(QueryResult::StopCandidates(candidates)) => {
+                for candidate in candidates {
+                    println!("{}", candidate);
+                }
+            },
+            Ok(QueryResult::Predictions(_)) => unreachable!(),
+            Err(error) => {
+                eprintln!("error: {:?}", error);
+                std::process::exit(1);
+            }
+        }
+        return;
+    }
+
+    if let Some(spec) = arg_matches.value_of("near") {
+        let coords: Result<Vec<f64>, _> = spec.split(',').map(|s| s.parse::<f64>()).collect();
+        let coords = match coords {
+            Ok(ref coords) if coords.len() == 3 => coords,
+            _ => {
+                eprintln!("error: --near expects LAT,LON,RADIUS_M");
+                std::process::exit(1);
+            }
+        };
+        let (lat, lon, radius_m) = (coords[0], coords[1], coords[2]);
+        match Request::near(lat, lon, radius_m).send(profile.as_ref()) {
+            Ok(QueryResult::Predictions(predictions)) => {
+                print_predictions(&predictions, format_output, compact_output, Some((lat, lon)));
+            },
+            Ok(QueryResult::StopCandidates(_)) => unreachable!(),
+            Err(error) => {
+                eprintln!("error: {:?}", error);
+                std::process::exit(1);
+            }
+        }
+        return;
+    }
+
+    if arg_matches.is_present("watch") {
+        let stop = match arg_matches.values_of("STOP") {
+            Some(mut stops) => {
+                let stop = stops.next().unwrap().to_string();
+                if stops.next().is_some() {
+                    eprintln!("error: --watch supports only a single STOP");
+                    std::process::exit(1);
+                }
+                stop
+            },
+            None => {
+                eprintln!("error: --watch requires a STOP");
+                std::process::exit(1);
+            }
+        };
+        watch(stop, profile.as_ref(), compact_output);
+        return;
+    }
+
+    let stops: Vec<String> = match arg_matches.values_of("STOP") {
+        Some(stops) => stops.map(|s| s.to_string()).collect(),
+        None => {
+            eprintln!("error: either STOP, --search <QUERY> or --near <LAT,LON,RADIUS_M> is required");
+            std::process::exit(1);
+        }
+    };
 
     // fire requests
-    let request_rxs: Vec<_> = stops.into_iter().map(|stop| {
+    let stop_rxs: Vec<_> = stops.into_iter().map(|stop| {
         let (tx, rx) = channel();
+        let profile = profile.clone();
+        let stop_for_thread = stop.clone();
         thread::spawn(move || {
-            tx.send(Request::with_stop_point_name(stop).send(base_url.to_string())).unwrap();
+            tx.send(Request::with_stop_point_name(stop_for_thread).send(profile.as_ref())).unwrap();
         });
-        rx
+        (stop, rx)
     }).collect();
 
-    // collect results
-    let results: Vec<_> = request_rxs.iter().map(|rx| {
-        match rx.recv().unwrap() {
-            Ok(res) => {
-                res
+    // collect results, keeping the ones that succeeded even if others failed
+    let mut any_failed = false;
+    let succeeded: Vec<(String, Predictions)> = stop_rxs.into_iter().filter_map(|(stop, rx)| {
+        match rx.recv() {
+            Ok(Ok(QueryResult::Predictions(predictions))) => {
+                Some((stop, predictions))
             },
-            Err(error) => {
-                println!("error: {:?}", error);
-                std::process::exit(1);
+            Ok(Ok(QueryResult::StopCandidates(_))) => unreachable!(),
+            Ok(Err(error)) => {
+                eprintln!("error: stop {:?}: {:?}", stop, error);
+                any_failed = true;
+                None
+            },
+            Err(_) => {
+                eprintln!("error: stop {:?}: worker thread did not respond", stop);
+                any_failed = true;
+                None
             }
         }
     }).collect();
-    let intersection = results.intersect(ordered).unwrap();
-    print!("{}", intersection.format(compact_output));
+
+    if succeeded.is_empty() {
+        eprintln!("error: all stops failed");
+        std::process::exit(1);
+    }
+
+    if ordered {
+        let predictions_only: Vec<Predictions> = succeeded.into_iter().map(|(_, p)| p).collect();
+        let intersection = predictions_only.intersect(ordered).unwrap();
+        print_predictions(&intersection, format_output, compact_output, None);
+    } else {
+        // unordered: trips don't have to visit every stop, so print each
+        // stop's own board instead of intersecting them away
+        for (stop, predictions) in succeeded {
+            println!("== {} ==", stop);
+            print_predictions(&predictions, format_output, compact_output, None);
+        }
+    }
+
+    if any_failed {
+        std::process::exit(1);
+    }
+}
+
+/// Renders `predictions` in the requested `--format`: the existing
+/// fixed-width table, or a JSON document for scripts and dashboards.
+fn print_predictions(predictions: &Predictions, format: &str, compact: bool, origin: Option<(f64, f64)>) {
+    match format {
+        "json" => {
+            println!("{}", serde_json::to_string(predictions).unwrap());
+        },
+        _ => {
+            print!("{}", predictions.format(compact, origin));
+        }
+    }
+}
+
+/// Continuously updating departure board for `stop_point_name`: applies
+/// each `StreamEvent` to a live table and re-renders it, clearing the
+/// terminal between frames.
+fn watch(stop_point_name: String, profile: &dyn UraProfile, compact: bool) {
+    use std::collections::HashMap;
+
+    let rx = match Request::with_stop_point_name(stop_point_name).send_stream(profile) {
+        Ok(rx) => rx,
+        Err(error) => {
+            eprintln!("error: {:?}", error);
+            std::process::exit(1);
+        }
+    };
+
+    let mut board: HashMap<TripId, Prediction> = HashMap::new();
+    for event in rx {
+        match event {
+            StreamEvent::Insert(prediction) => {
+                board.insert(prediction.trip_id, prediction);
+            },
+            StreamEvent::Delete(trip_id) => {
+                board.remove(&trip_id);
+            },
+            StreamEvent::UpdateTime(trip_id, estimated_time) => {
+                if let Some(prediction) = board.get_mut(&trip_id) {
+                    prediction.estimated_time = estimated_time;
+                }
+            }
+        }
+
+        let mut predictions: Vec<Prediction> = board.values().cloned().collect();
+        predictions.sort_by_key(|p| p.estimated_time);
+        let snapshot = Predictions {
+            time: Local::now(),
+            predictions: predictions
+        };
+
+        print!("\x1B[2J\x1B[H{}", snapshot.format(compact, None));
+    }
 }
 